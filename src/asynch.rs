@@ -0,0 +1,172 @@
+//! Async controller built on the DRDY interrupt instead of `nb` busy-polling.
+//!
+//! This mirrors the blocking `ActiveBlueNRG` controller, but instead of returning
+//! `nb::Error::WouldBlock` and forcing the caller to spin, it `await`s the data-ready
+//! line directly. This lets BlueNRG integrate into executor-based firmware (e.g.
+//! embassy) where the DRDY GPIO is wired to an EXTI/async waker.
+
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiBus;
+
+use crate::proto::ProtoRead;
+use crate::{ble, hal, nb, BlueNRG, Error};
+
+/// Async counterpart of [`ActiveBlueNRG`](crate::ActiveBlueNRG). Obtained from
+/// [`BlueNRG::with_spi_async`].
+pub struct ActiveBlueNRGAsync<'spi, 'dbuf: 'spi, SPI: 'spi, OutputPin: 'spi, InputPin: 'spi> {
+    pub(crate) d: &'spi mut BlueNRG<'dbuf, SPI, OutputPin, InputPin>,
+    pub(crate) spi: &'spi mut SPI,
+}
+
+impl<'spi, 'dbuf, SPI, OutputPin, InputPin, E, PinE>
+    ActiveBlueNRGAsync<'spi, 'dbuf, SPI, OutputPin, InputPin>
+where
+    SPI: SpiBus<u8, Error = E>,
+    OutputPin: hal::digital::v2::OutputPin<Error = PinE>,
+    InputPin: hal::digital::v2::InputPin<Error = PinE> + Wait<Error = PinE>,
+{
+    async fn try_write(&mut self, header: &[u8], payload: &[u8]) -> Result<(), Error<E, PinE>> {
+        loop {
+            let mut write_header = [0x0a, 0x00, 0x00, 0x00, 0x00];
+            self.spi
+                .transfer_in_place(&mut write_header)
+                .await
+                .map_err(Error::Comm)?;
+
+            match crate::parse_spi_header(&write_header) {
+                Ok((write_len, _read_len))
+                    if (write_len as usize) >= header.len() + payload.len() =>
+                {
+                    self.spi.write(header).await.map_err(Error::Comm)?;
+                    self.spi.write(payload).await.map_err(Error::Comm)?;
+                    return Ok(());
+                }
+                Ok(_) | Err(nb::Error::WouldBlock) => {
+                    // The controller isn't ready for the full write yet; wait for DRDY
+                    // to go high instead of busy-polling and retry. DRDY is a level,
+                    // not a pulse, so this must be `wait_for_high`, not an edge wait
+                    // (which would never fire if DRDY was already high from a pending
+                    // read).
+                    self.d.data_ready.wait_for_high().await.map_err(Error::Pin)?;
+                }
+                Err(nb::Error::Other(e)) => return Err(e),
+            }
+        }
+    }
+
+    async fn read_available_data(&mut self) -> Result<(), Error<E, PinE>> {
+        // DRDY is held high for as long as data is pending, so this must wait for the
+        // level rather than an edge: after the first packet in a burst is drained,
+        // DRDY is often still high, and a rising-edge wait would never fire.
+        self.d.data_ready.wait_for_high().await.map_err(Error::Pin)?;
+
+        let mut read_header = [0x0b, 0x00, 0x00, 0x00, 0x00];
+        self.spi
+            .transfer_in_place(&mut read_header)
+            .await
+            .map_err(Error::Comm)?;
+
+        let (_write_len, read_len) = match crate::parse_spi_header(&read_header) {
+            Ok(lens) => lens,
+            Err(nb::Error::WouldBlock) => return Ok(()),
+            Err(nb::Error::Other(e)) => return Err(e),
+        };
+        let mut bytes_available = read_len as usize;
+        let mut dropped = 0usize;
+        while bytes_available > 0 {
+            match self.d.rx_buffer.next_chunk(bytes_available) {
+                crate::cb::NextChunk::Discard(n) => {
+                    let mut scratch = [0u8; crate::cb::DRAIN_CHUNK];
+                    self.spi
+                        .transfer_in_place(&mut scratch[..n])
+                        .await
+                        .map_err(Error::Comm)?;
+                    bytes_available -= n;
+                    dropped += n;
+                }
+                crate::cb::NextChunk::Store(n) => {
+                    {
+                        let rx = self.d.rx_buffer.next_mut_slice(n);
+                        for b in rx.iter_mut() {
+                            *b = 0;
+                        }
+                        self.spi
+                            .transfer_in_place(rx)
+                            .await
+                            .map_err(Error::Comm)?;
+                    }
+                    bytes_available -= n;
+                }
+            }
+        }
+
+        if dropped > 0 {
+            return Err(Error::RxBufferFull { dropped });
+        }
+
+        Ok(())
+    }
+
+    fn take_next_event(&mut self) -> Option<Result<ble::Event, Error<E, PinE>>> {
+        if self.d.rx_buffer.available_len() < ble::hci::EVENT_PACKET_HEADER_LENGTH {
+            return None;
+        }
+
+        let event_type = self.d.rx_buffer.peek_u8(0);
+        let param_len = self.d.rx_buffer.peek_u8(1) as usize;
+        let event_len = ble::hci::EVENT_PACKET_HEADER_LENGTH + param_len;
+        if self.d.rx_buffer.available_len() < event_len {
+            return None;
+        }
+
+        // `param_len` is a `u8`, so `event_len` never exceeds `MAX_EVENT_SIZE`. The
+        // header fields were already peeked above, so skip past them and read the rest
+        // of the packet off the cursor.
+        let mut bytes: [u8; crate::MAX_EVENT_SIZE] = [0; crate::MAX_EVENT_SIZE];
+        bytes[0] = event_type;
+        bytes[1] = param_len as u8;
+        self.d.rx_buffer.skip(ble::hci::EVENT_PACKET_HEADER_LENGTH);
+        for b in bytes[ble::hci::EVENT_PACKET_HEADER_LENGTH..event_len].iter_mut() {
+            *b = self.d.rx_buffer.read_u8();
+        }
+        Some(
+            ble::hci::parse_event(ble::hci::EventPacket(&bytes[..event_len])).map_err(Error::BLE),
+        )
+    }
+
+    async fn try_read(&mut self) -> Result<ble::Event, Error<E, PinE>> {
+        // The blocking controller can stash a dropped-byte count here if an overflow
+        // and a ready event land in the same call; surface it before anything else so
+        // it isn't lost if the caller switches between `with_spi` and `with_spi_async`.
+        if self.d.pending_overflow > 0 {
+            let dropped = core::mem::replace(&mut self.d.pending_overflow, 0);
+            return Err(Error::RxBufferFull { dropped });
+        }
+
+        loop {
+            self.read_available_data().await?;
+            if let Some(result) = self.take_next_event() {
+                return result;
+            }
+        }
+    }
+
+    /// Writes a complete HCI packet (header and payload) out over SPI, awaiting the
+    /// DRDY line as needed instead of busy-polling.
+    pub async fn write(&mut self, header: &[u8], payload: &[u8]) -> Result<(), Error<E, PinE>> {
+        self.d.chip_select.set_low().map_err(Error::Pin)?;
+        let result = self.try_write(header, payload).await;
+        let cs_result = self.d.chip_select.set_high();
+
+        result.and_then(|()| cs_result.map_err(Error::Pin))
+    }
+
+    /// Awaits and returns the next BLE event from the controller.
+    pub async fn read(&mut self) -> Result<ble::Event, Error<E, PinE>> {
+        self.d.chip_select.set_low().map_err(Error::Pin)?;
+        let result = self.try_read().await;
+        let cs_result = self.d.chip_select.set_high();
+
+        result.and_then(|event| cs_result.map(|()| event).map_err(Error::Pin))
+    }
+}
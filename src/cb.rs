@@ -0,0 +1,194 @@
+//! A small circular byte buffer used to stage data received over SPI until a full HCI
+//! packet has arrived.
+
+use core::cmp::min;
+
+use crate::proto::ProtoRead;
+
+/// Size of each discard batch when the buffer is full and incoming bytes must be
+/// drained straight off SPI instead of being stored, to keep the next read header in
+/// sync with the controller.
+pub(crate) const DRAIN_CHUNK: usize = 8;
+
+/// What to do with the next `bytes_available` bytes still due in over SPI: store them,
+/// or (if the buffer has no room left) discard a batch of them instead. Shared between
+/// the blocking and async controllers so their drain/overflow bookkeeping can't drift.
+pub(crate) enum NextChunk {
+    /// Store `len` bytes: write them into `next_mut_slice(len)`.
+    Store(usize),
+    /// The buffer is full; discard `len` bytes instead of storing them.
+    Discard(usize),
+}
+
+pub struct Buffer<'buf, T: 'buf> {
+    buf: &'buf mut [T],
+    // Index of the next element to be written.
+    head: usize,
+    // Index of the next element to be read.
+    tail: usize,
+    // Number of valid, unread elements currently stored.
+    len: usize,
+}
+
+impl<'buf, T: Copy + Default> Buffer<'buf, T> {
+    pub fn new(buf: &'buf mut [T]) -> Buffer<'buf, T> {
+        Buffer {
+            buf: buf,
+            head: 0,
+            tail: 0,
+            len: 0,
+        }
+    }
+
+    /// Total number of elements the buffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Number of unread elements currently stored in the buffer.
+    pub fn available_len(&self) -> usize {
+        self.len
+    }
+
+    /// Number of additional elements that can be written before the buffer is full.
+    pub fn free_len(&self) -> usize {
+        self.capacity() - self.len
+    }
+
+    /// Length of the next writable slice starting at `head`, without wrapping around
+    /// the end of the backing storage and without overwriting unread data.
+    pub fn next_contiguous_slice_len(&self) -> usize {
+        let until_wrap = self.capacity() - self.head;
+        if until_wrap < self.free_len() {
+            until_wrap
+        } else {
+            self.free_len()
+        }
+    }
+
+    /// Returns a mutable slice of exactly `len` elements starting at `head`, and marks
+    /// those elements as written. `len` must not exceed `next_contiguous_slice_len()`.
+    pub fn next_mut_slice(&mut self, len: usize) -> &mut [T] {
+        assert!(len <= self.next_contiguous_slice_len());
+
+        let capacity = self.capacity();
+        let head = self.head;
+        self.head = (head + len) % capacity;
+        self.len += len;
+
+        &mut self.buf[head..head + len]
+    }
+
+    /// Decides how to handle the next chunk of `bytes_available` bytes still due in
+    /// over SPI: store them if there's room, or discard a batch of at most `DRAIN_CHUNK`
+    /// bytes if there isn't.
+    pub(crate) fn next_chunk(&self, bytes_available: usize) -> NextChunk {
+        let contiguous_len = self.next_contiguous_slice_len();
+        if contiguous_len == 0 {
+            NextChunk::Discard(min(bytes_available, DRAIN_CHUNK))
+        } else {
+            NextChunk::Store(min(bytes_available, contiguous_len))
+        }
+    }
+}
+
+/// Lets HCI event framing be read directly off the circular buffer (via `peek_u8` and
+/// `skip`) instead of draining it into a linear scratch buffer first.
+impl<'buf> ProtoRead for Buffer<'buf, u8> {
+    fn read_u8(&mut self) -> u8 {
+        let b = self.peek_u8(0);
+        self.tail = (self.tail + 1) % self.capacity();
+        self.len -= 1;
+        b
+    }
+
+    fn peek_u8(&self, offset: usize) -> u8 {
+        assert!(offset < self.len);
+
+        self.buf[(self.tail + offset) % self.capacity()]
+    }
+
+    fn skip(&mut self, n: usize) {
+        assert!(n <= self.len);
+
+        self.tail = (self.tail + n) % self.capacity();
+        self.len -= n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_and_drains_without_wrapping() {
+        let mut backing = [0u8; 4];
+        let mut buf = Buffer::new(&mut backing);
+        assert_eq!(buf.capacity(), 4);
+        assert_eq!(buf.free_len(), 4);
+
+        buf.next_mut_slice(3).copy_from_slice(&[1, 2, 3]);
+        assert_eq!(buf.available_len(), 3);
+        assert_eq!(buf.free_len(), 1);
+
+        assert_eq!(buf.read_u8(), 1);
+        assert_eq!(buf.read_u8(), 2);
+        assert_eq!(buf.read_u8(), 3);
+        assert_eq!(buf.available_len(), 0);
+        assert_eq!(buf.free_len(), 4);
+    }
+
+    #[test]
+    fn next_contiguous_slice_len_stops_before_wraparound() {
+        let mut backing = [0u8; 4];
+        let mut buf = Buffer::new(&mut backing);
+        buf.next_mut_slice(3);
+        buf.skip(3);
+
+        // `head` is now at 3, so only one element is contiguous before the backing
+        // array wraps, even though the buffer is otherwise empty.
+        assert_eq!(buf.next_contiguous_slice_len(), 1);
+        assert_eq!(buf.free_len(), 4);
+    }
+
+    #[test]
+    fn peek_and_read_u8_read_in_order_across_wraparound() {
+        let mut backing = [0u8; 4];
+        let mut buf = Buffer::new(&mut backing);
+        buf.next_mut_slice(3).copy_from_slice(&[1, 2, 3]);
+        buf.skip(2);
+        // Only one contiguous slot remains before the backing array wraps.
+        buf.next_mut_slice(1).copy_from_slice(&[4]);
+        buf.next_mut_slice(1).copy_from_slice(&[5]);
+
+        // Buffer now holds [3, 4, 5] wrapped around the backing storage.
+        assert_eq!(buf.peek_u8(0), 3);
+        assert_eq!(buf.peek_u8(2), 5);
+
+        assert_eq!(buf.read_u8(), 3);
+        assert_eq!(buf.read_u8(), 4);
+        assert_eq!(buf.read_u8(), 5);
+        assert_eq!(buf.available_len(), 0);
+    }
+
+    #[test]
+    fn reports_full_when_no_contiguous_space_remains() {
+        let mut backing = [0u8; 4];
+        let mut buf = Buffer::new(&mut backing);
+        buf.next_mut_slice(4);
+        assert_eq!(buf.next_contiguous_slice_len(), 0);
+        assert_eq!(buf.free_len(), 0);
+    }
+
+    #[test]
+    fn skip_discards_without_returning() {
+        let mut backing = [0u8; 4];
+        let mut buf = Buffer::new(&mut backing);
+        buf.next_mut_slice(4).copy_from_slice(&[1, 2, 3, 4]);
+
+        buf.skip(2);
+        assert_eq!(buf.available_len(), 2);
+        assert_eq!(buf.read_u8(), 3);
+        assert_eq!(buf.read_u8(), 4);
+    }
+}
@@ -1,17 +1,33 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 extern crate ble;
+#[cfg(feature = "defmt")]
+extern crate defmt;
 extern crate embedded_hal as hal;
+#[cfg(feature = "async")]
+extern crate embedded_hal_async;
 extern crate nb;
 
 use core::marker::PhantomData;
 
 mod cb;
+mod proto;
+#[cfg(feature = "async")]
+mod asynch;
+
+use proto::ProtoRead;
+
+#[cfg(feature = "async")]
+pub use asynch::ActiveBlueNRGAsync;
 
 pub struct BlueNRG<'buf, SPI, OutputPin, InputPin> {
     chip_select: OutputPin,
     data_ready: InputPin,
     rx_buffer: cb::Buffer<'buf, u8>,
+    // Bytes dropped by a previous `read_available_data` overflow that `try_read`
+    // hasn't reported yet, because an event was also available in that same call.
+    // Surfaced on the next call instead of being silently lost.
+    pending_overflow: usize,
     _spi: PhantomData<SPI>,
 }
 
@@ -21,38 +37,65 @@ struct ActiveBlueNRG<'spi, 'dbuf: 'spi, SPI: 'spi, OutputPin: 'spi, InputPin: 's
 }
 
 #[derive(Copy, Clone, Debug)]
-pub enum Error<E> {
+pub enum Error<E, PinE> {
     Comm(E),
+    Pin(PinE),
     BLE(ble::hci::EventError),
+    /// The controller reported more bytes than the RX buffer had room for. The bytes
+    /// were still clocked off of SPI (to keep packet framing intact) but dropped
+    /// instead of being stored; `dropped` is how many. Callers should grow their RX
+    /// buffer or read events more often to avoid this.
+    RxBufferFull { dropped: usize },
 }
 
-fn parse_spi_header<E>(header: &[u8; 5]) -> Result<(u16, u16), nb::Error<Error<E>>> {
-    const BNRG_READY: u8 = 0x02;
-    if header[0] != BNRG_READY {
-        Err(nb::Error::WouldBlock)
-    } else {
-        Ok((
-            (header[2] as u16) << 8 | header[1] as u16,
-            (header[4] as u16) << 8 | header[3] as u16,
-        ))
+/// Size of the largest possible HCI event packet: the packet header plus the
+/// largest `param_len` a single `u8` can encode. Sized to this so that
+/// `take_next_event` can never be asked to copy more than the buffer holds,
+/// no matter what `param_len` the controller reports.
+pub(crate) const MAX_EVENT_SIZE: usize = ble::hci::EVENT_PACKET_HEADER_LENGTH + u8::MAX as usize;
+
+// `ble::hci::EventError` doesn't implement `defmt::Format` (the `ble` crate isn't
+// defmt-aware), so this can't be a plain `#[derive(defmt::Format)]`: format that
+// variant via `Debug2Format` instead of requiring a `Format` bound on it.
+#[cfg(feature = "defmt")]
+impl<E: defmt::Format, PinE: defmt::Format> defmt::Format for Error<E, PinE> {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            Error::Comm(e) => defmt::write!(f, "Comm({})", e),
+            Error::Pin(e) => defmt::write!(f, "Pin({})", e),
+            Error::BLE(e) => defmt::write!(f, "BLE({})", defmt::Debug2Format(e)),
+            Error::RxBufferFull { dropped } => {
+                defmt::write!(f, "RxBufferFull {{ dropped: {} }}", dropped)
+            }
+        }
     }
 }
 
-fn max<T: PartialOrd>(lhs: T, rhs: T) -> T {
-    if lhs < rhs {
-        rhs
+fn parse_spi_header<E, PinE>(header: &[u8; 5]) -> Result<(u16, u16), nb::Error<Error<E, PinE>>> {
+    const BNRG_READY: u8 = 0x02;
+
+    let mut r = proto::SliceReader::new(header);
+    if r.read_u8() != BNRG_READY {
+        Err(nb::Error::WouldBlock)
     } else {
-        lhs
+        let write_len = r.read_u16_le();
+        let read_len = r.read_u16_le();
+
+        #[cfg(feature = "defmt")]
+        defmt::trace!("spi header: write_len={} read_len={}", write_len, read_len);
+
+        Ok((write_len, read_len))
     }
 }
 
-impl<'spi, 'dbuf, SPI, OutputPin, InputPin, E> ActiveBlueNRG<'spi, 'dbuf, SPI, OutputPin, InputPin>
+impl<'spi, 'dbuf, SPI, OutputPin, InputPin, E, PinE>
+    ActiveBlueNRG<'spi, 'dbuf, SPI, OutputPin, InputPin>
 where
     SPI: hal::blocking::spi::Transfer<u8, Error = E> + hal::blocking::spi::Write<u8, Error = E>,
-    OutputPin: hal::digital::OutputPin,
-    InputPin: hal::digital::InputPin,
+    OutputPin: hal::digital::v2::OutputPin<Error = PinE>,
+    InputPin: hal::digital::v2::InputPin<Error = PinE>,
 {
-    fn try_write(&mut self, header: &[u8], payload: &[u8]) -> nb::Result<(), Error<E>> {
+    fn try_write(&mut self, header: &[u8], payload: &[u8]) -> nb::Result<(), Error<E, PinE>> {
         let mut write_header = [0x0a, 0x00, 0x00, 0x00, 0x00];
         self.spi
             .transfer(&mut write_header)
@@ -73,7 +116,15 @@ where
         Ok(())
     }
 
-    fn try_read(&mut self) -> nb::Result<ble::Event, Error<E>> {
+    fn try_read(&mut self) -> nb::Result<ble::Event, Error<E, PinE>> {
+        // A previous call may have dropped bytes to an overflow but returned an event
+        // instead of reporting it (see below); surface that now, before doing anything
+        // else, so it's never silently lost.
+        if self.d.pending_overflow > 0 {
+            let dropped = core::mem::replace(&mut self.d.pending_overflow, 0);
+            return Err(nb::Error::Other(Error::RxBufferFull { dropped }));
+        }
+
         // Always read whatever data is available, then get the next event from the internal buffer.
         // If there is no valid event, then use the return value from reading the data.  This
         // ensures that we can get a known pending event even if reading data would block.
@@ -83,12 +134,23 @@ where
                 Ok(_) => Err(nb::Error::WouldBlock),
                 Err(e) => Err(e),
             },
-            x => x,
+            x => {
+                // An event was available even though `data_result` may carry a dropped-byte
+                // overflow; stash it instead of discarding it so the next call reports it.
+                if let Err(nb::Error::Other(Error::RxBufferFull { dropped })) = data_result {
+                    self.d.pending_overflow += dropped;
+                }
+                x
+            }
         }
     }
 
-    fn read_available_data(&mut self) -> nb::Result<(), Error<E>> {
-        if !self.d.data_ready() {
+    fn read_available_data(&mut self) -> nb::Result<(), Error<E, PinE>> {
+        if !self
+            .d
+            .data_ready()
+            .map_err(|e| nb::Error::Other(Error::Pin(e)))?
+        {
             return Err(nb::Error::WouldBlock);
         }
 
@@ -99,76 +161,132 @@ where
 
         let (_write_len, read_len) = parse_spi_header(&read_header)?;
         let mut bytes_available = read_len as usize;
-        while bytes_available > 0 && self.d.rx_buffer.next_contiguous_slice_len() > 0 {
-            let transfer_count = max(
-                bytes_available,
-                self.d.rx_buffer.next_contiguous_slice_len(),
-            );
-            {
-                let rx = self.d.rx_buffer.next_mut_slice(transfer_count);
-                for i in 0..rx.len() {
-                    rx[i] = 0;
+        #[cfg(feature = "defmt")]
+        let mut bytes_pulled = 0usize;
+        let mut dropped = 0usize;
+        while bytes_available > 0 {
+            match self.d.rx_buffer.next_chunk(bytes_available) {
+                cb::NextChunk::Discard(n) => {
+                    let mut scratch = [0u8; cb::DRAIN_CHUNK];
+                    self.spi
+                        .transfer(&mut scratch[..n])
+                        .map_err(|e| nb::Error::Other(Error::Comm(e)))?;
+                    bytes_available -= n;
+                    dropped += n;
+                }
+                cb::NextChunk::Store(n) => {
+                    {
+                        let rx = self.d.rx_buffer.next_mut_slice(n);
+                        for b in rx.iter_mut() {
+                            *b = 0;
+                        }
+                        self.spi
+                            .transfer(rx)
+                            .map_err(|e| nb::Error::Other(Error::Comm(e)))?;
+                    }
+                    bytes_available -= n;
+                    #[cfg(feature = "defmt")]
+                    {
+                        bytes_pulled += n;
+                    }
                 }
-                self.spi
-                    .transfer(rx)
-                    .map_err(|e| nb::Error::Other(Error::Comm(e)))?;
             }
-            bytes_available -= transfer_count;
+        }
+
+        #[cfg(feature = "defmt")]
+        defmt::trace!("read_available_data: pulled {} bytes", bytes_pulled);
+
+        if dropped > 0 {
+            return Err(nb::Error::Other(Error::RxBufferFull { dropped }));
         }
 
         Ok(())
     }
 
-    fn take_next_event(&mut self) -> nb::Result<ble::Event, Error<E>> {
+    fn take_next_event(&mut self) -> nb::Result<ble::Event, Error<E, PinE>> {
         if self.d.rx_buffer.available_len() < ble::hci::EVENT_PACKET_HEADER_LENGTH {
             return Err(nb::Error::WouldBlock);
         }
 
-        let param_len = self.d.rx_buffer.peek(1) as usize;
-        if self.d.rx_buffer.available_len() < ble::hci::EVENT_PACKET_HEADER_LENGTH + param_len {
+        let event_type = self.d.rx_buffer.peek_u8(0);
+        let param_len = self.d.rx_buffer.peek_u8(1) as usize;
+        let event_len = ble::hci::EVENT_PACKET_HEADER_LENGTH + param_len;
+        if self.d.rx_buffer.available_len() < event_len {
             return Err(nb::Error::WouldBlock);
         }
 
-        const MAX_EVENT_SIZE: usize = 128;
+        #[cfg(feature = "defmt")]
+        defmt::trace!(
+            "take_next_event: event_type={=u8} param_len={}",
+            event_type,
+            param_len
+        );
+
+        // `param_len` is a `u8`, so `event_len` never exceeds `MAX_EVENT_SIZE`. The
+        // header fields were already peeked above, so skip past them and read the rest
+        // of the packet off the cursor.
         let mut bytes: [u8; MAX_EVENT_SIZE] = [0; MAX_EVENT_SIZE];
-        self.d
-            .rx_buffer
-            .take_slice(ble::hci::EVENT_PACKET_HEADER_LENGTH + param_len, &mut bytes);
-        ble::hci::parse_event(ble::hci::EventPacket(&bytes))
-            .map_err(|e| nb::Error::Other(Error::BLE(e)))
+        bytes[0] = event_type;
+        bytes[1] = param_len as u8;
+        self.d.rx_buffer.skip(ble::hci::EVENT_PACKET_HEADER_LENGTH);
+        for b in bytes[ble::hci::EVENT_PACKET_HEADER_LENGTH..event_len].iter_mut() {
+            *b = self.d.rx_buffer.read_u8();
+        }
+        let event = ble::hci::parse_event(ble::hci::EventPacket(&bytes[..event_len]))
+            .map_err(|e| nb::Error::Other(Error::BLE(e)))?;
+
+        #[cfg(feature = "defmt")]
+        defmt::trace!(
+            "take_next_event: parsed {}",
+            defmt::Debug2Format(&event)
+        );
+
+        Ok(event)
     }
 }
 
-impl<'spi, 'dbuf, SPI, OutputPin, InputPin, E> ble::Controller
+impl<'spi, 'dbuf, SPI, OutputPin, InputPin, E, PinE> ble::Controller
     for ActiveBlueNRG<'spi, 'dbuf, SPI, OutputPin, InputPin>
 where
     SPI: hal::blocking::spi::Transfer<u8, Error = E> + hal::blocking::spi::Write<u8, Error = E>,
-    OutputPin: hal::digital::OutputPin,
-    InputPin: hal::digital::InputPin,
+    OutputPin: hal::digital::v2::OutputPin<Error = PinE>,
+    InputPin: hal::digital::v2::InputPin<Error = PinE>,
 {
-    type Error = Error<E>;
+    type Error = Error<E, PinE>;
 
     fn write(&mut self, header: &[u8], payload: &[u8]) -> nb::Result<(), Self::Error> {
-        self.d.chip_select.set_low();
+        self.d
+            .chip_select
+            .set_low()
+            .map_err(|e| nb::Error::Other(Error::Pin(e)))?;
         let result = self.try_write(header, payload);
-        self.d.chip_select.set_high();
+        let cs_result = self.d.chip_select.set_high();
 
-        result
+        // The CS line must be restored even if the body failed, but a body error always
+        // takes priority over a failure to restore CS.
+        result.and_then(|()| cs_result.map_err(|e| nb::Error::Other(Error::Pin(e))))
     }
 
     fn read(&mut self) -> nb::Result<ble::Event, Self::Error> {
-        self.d.chip_select.set_low();
+        self.d
+            .chip_select
+            .set_low()
+            .map_err(|e| nb::Error::Other(Error::Pin(e)))?;
         let result = self.try_read();
-        self.d.chip_select.set_high();
+        let cs_result = self.d.chip_select.set_high();
 
-        result
+        result.and_then(|event| {
+            cs_result
+                .map(|()| event)
+                .map_err(|e| nb::Error::Other(Error::Pin(e)))
+        })
     }
 }
 
-impl<'buf, SPI, OutputPin, InputPin> BlueNRG<'buf, SPI, OutputPin, InputPin>
+impl<'buf, SPI, OutputPin, InputPin, PinE> BlueNRG<'buf, SPI, OutputPin, InputPin>
 where
-    OutputPin: hal::digital::OutputPin,
-    InputPin: hal::digital::InputPin,
+    OutputPin: hal::digital::v2::OutputPin<Error = PinE>,
+    InputPin: hal::digital::v2::InputPin<Error = PinE>,
 {
     pub fn new<Reset>(
         rx_buffer: &'buf mut [u8],
@@ -185,25 +303,55 @@ where
             chip_select: cs,
             rx_buffer: cb::Buffer::new(rx_buffer),
             data_ready: dr,
+            pending_overflow: 0,
             _spi: PhantomData,
         }
     }
 
     pub fn with_spi<'spi, T, F, E>(&mut self, spi: &'spi mut SPI, body: F) -> T
     where
-        F: FnOnce(&mut ble::Controller<Error = Error<E>>) -> T,
+        F: FnOnce(&mut ble::Controller<Error = Error<E, PinE>>) -> T,
         SPI: hal::blocking::spi::transfer::Default<u8, Error = E>
             + hal::blocking::spi::write::Default<u8, Error = E>,
     {
         let mut active = ActiveBlueNRG::<SPI, OutputPin, InputPin> { spi: spi, d: self };
-        body(&mut active as &mut ble::Controller<Error = Error<E>>)
+        body(&mut active as &mut ble::Controller<Error = Error<E, PinE>>)
     }
 
-    fn data_ready(&self) -> bool {
+    fn data_ready(&self) -> Result<bool, PinE> {
         self.data_ready.is_high()
     }
+
+    /// Number of bytes currently buffered and waiting to be parsed into events.
+    pub fn rx_bytes_available(&self) -> usize {
+        self.rx_buffer.available_len()
+    }
+
+    /// Number of additional bytes the RX buffer can hold before `Error::RxBufferFull`
+    /// starts getting reported. Size the buffer passed to `new` so this stays
+    /// comfortably above the largest event the controller can send.
+    pub fn rx_capacity_remaining(&self) -> usize {
+        self.rx_buffer.free_len()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'buf, SPI, OutputPin, InputPin, PinE> BlueNRG<'buf, SPI, OutputPin, InputPin>
+where
+    OutputPin: hal::digital::v2::OutputPin<Error = PinE>,
+    InputPin: hal::digital::v2::InputPin<Error = PinE> + embedded_hal_async::digital::Wait<Error = PinE>,
+{
+    /// Like [`with_spi`](Self::with_spi), but returns a controller that `await`s the
+    /// DRDY interrupt instead of busy-polling, for use with async SPI peripherals.
+    pub fn with_spi_async<'spi>(
+        &'spi mut self,
+        spi: &'spi mut SPI,
+    ) -> ActiveBlueNRGAsync<'spi, 'buf, SPI, OutputPin, InputPin> {
+        ActiveBlueNRGAsync { spi: spi, d: self }
+    }
 }
 
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Version {
     pub hw_version: u8,
     pub major: u8,
@@ -0,0 +1,103 @@
+//! A small cursor abstraction for reading packed, little/big-endian protocol data
+//! (SPI headers, HCI event framing) without scattering raw index arithmetic and
+//! bit-shifting across the crate.
+
+pub trait ProtoRead {
+    /// Reads and consumes the next byte.
+    fn read_u8(&mut self) -> u8;
+
+    /// Returns the byte `offset` positions ahead of the read cursor without
+    /// consuming it.
+    fn peek_u8(&self, offset: usize) -> u8;
+
+    /// Consumes `n` bytes without returning them.
+    fn skip(&mut self, n: usize);
+
+    /// Reads and consumes a little-endian `u16`.
+    fn read_u16_le(&mut self) -> u16 {
+        let lo = self.read_u8() as u16;
+        let hi = self.read_u8() as u16;
+        (hi << 8) | lo
+    }
+
+    /// Reads and consumes a big-endian `u16`.
+    fn read_u16_be(&mut self) -> u16 {
+        let hi = self.read_u8() as u16;
+        let lo = self.read_u8() as u16;
+        (hi << 8) | lo
+    }
+}
+
+/// A `ProtoRead` cursor over a plain byte slice, used for the fixed-size SPI headers.
+pub struct SliceReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    pub fn new(buf: &'a [u8]) -> SliceReader<'a> {
+        SliceReader { buf: buf, pos: 0 }
+    }
+}
+
+impl<'a> ProtoRead for SliceReader<'a> {
+    fn read_u8(&mut self) -> u8 {
+        let b = self.buf[self.pos];
+        self.pos += 1;
+        b
+    }
+
+    fn peek_u8(&self, offset: usize) -> u8 {
+        self.buf[self.pos + offset]
+    }
+
+    fn skip(&mut self, n: usize) {
+        self.pos += n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_bytes_in_order() {
+        let buf = [0x01, 0x02, 0x03];
+        let mut r = SliceReader::new(&buf);
+        assert_eq!(r.read_u8(), 0x01);
+        assert_eq!(r.read_u8(), 0x02);
+        assert_eq!(r.read_u8(), 0x03);
+    }
+
+    #[test]
+    fn reads_little_endian_u16() {
+        let buf = [0x34, 0x12];
+        let mut r = SliceReader::new(&buf);
+        assert_eq!(r.read_u16_le(), 0x1234);
+    }
+
+    #[test]
+    fn reads_big_endian_u16() {
+        let buf = [0x12, 0x34];
+        let mut r = SliceReader::new(&buf);
+        assert_eq!(r.read_u16_be(), 0x1234);
+    }
+
+    #[test]
+    fn peek_does_not_consume() {
+        let buf = [0xaa, 0xbb, 0xcc];
+        let mut r = SliceReader::new(&buf);
+        assert_eq!(r.peek_u8(1), 0xbb);
+        assert_eq!(r.read_u8(), 0xaa);
+        assert_eq!(r.peek_u8(0), 0xbb);
+    }
+
+    #[test]
+    fn skip_advances_past_unread_bytes() {
+        let buf = [0x01, 0x02, 0x03, 0x04];
+        let mut r = SliceReader::new(&buf);
+        r.skip(2);
+        assert_eq!(r.read_u8(), 0x03);
+        assert_eq!(r.read_u8(), 0x04);
+    }
+}